@@ -1,40 +1,58 @@
 use osstrtools::OsStrConcat;
 use question::{Answer, Question};
-use std::collections::HashMap;
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
 use std::env;
-use std::ffi::{OsStr, OsString};
+use std::ffi::OsStr;
 use std::fs::{self, DirEntry};
+use std::io;
 use std::path::{Path, PathBuf};
 use thiserror::Error;
 
 mod util;
-use util::tmpfile;
+use util::{exchange, tmpfile};
 
 const HELP: &str = "\
 Usage: quiren [options] [dir]
 
 Modes:
-    <default>           Rename mode: Rename files modified in the editor
-    -d, --delete-mode   Delete mode: Delete files removed in the editor
+    <default>                       Rename mode: Rename files modified in the editor
+    -d, --delete-mode               Delete mode: Delete files removed in the editor
+    -s, --substitute PATTERN REPL   Substitute mode: Regex-rename every entry, no editor
 
 Options:
-    -h, --help          Prints help information
-    -r, --retry         Re-enters the editor after an error
-    -n, --dry-run       Show changes and ask for confirmation
-    -t, --trash         Trash files instead of deleting them
+    -h, --help           Prints help information
+    -r, --retry          Re-enters the editor after an invalid edit, annotating offending lines
+    -n, --dry-run        Show changes and ask for confirmation
+    -t, --trash          Trash files instead of deleting them
+    -l, --literal        Match the substitute PATTERN as plain text
+    -e, --allow-escape   Allow renames to move files outside of dir
+    -R, --recursive      Descend into subdirectories, listing relative paths
+        --max-depth N    Limit how many levels --recursive descends
+        --no-rollback    Don't undo completed changes if the batch fails partway
 ";
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Mode {
     Rename,
-    Delete
+    Delete,
+    Substitute {
+        pattern: String,
+        replacement: String,
+        literal: bool,
+    },
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Args {
     mode: Mode,
     dryrun: bool,
     trash: bool,
+    retry: bool,
+    allow_escape: bool,
+    recursive: bool,
+    max_depth: Option<usize>,
+    no_rollback: bool,
 }
 
 fn main() -> Result<(), main_error::MainError> {
@@ -44,10 +62,24 @@ fn main() -> Result<(), main_error::MainError> {
     let retry = pargs.contains(["-r", "--retry"]);
     let dryrun = pargs.contains(["-n", "--dry-run"]);
     let trash = pargs.contains(["-t", "--trash"]);
+    let literal = pargs.contains(["-l", "--literal"]);
+    let allow_escape = pargs.contains(["-e", "--allow-escape"]);
+    let recursive = pargs.contains(["-R", "--recursive"]);
+    let max_depth: Option<usize> = pargs.opt_value_from_str("--max-depth")?;
+    let no_rollback = pargs.contains("--no-rollback");
 
     let delete_mode = pargs.contains(["-d", "--delete-mode"]);
-
-    let mode = if delete_mode {
+    let substitute_mode = pargs.contains(["-s", "--substitute"]);
+
+    let mode = if substitute_mode {
+        let pattern: String = pargs.free_from_str()?;
+        let replacement: String = pargs.free_from_str()?;
+        Mode::Substitute {
+            pattern,
+            replacement,
+            literal,
+        }
+    } else if delete_mode {
         Mode::Delete
     } else {
         // The default behaviour is to rename files.
@@ -64,20 +96,19 @@ fn main() -> Result<(), main_error::MainError> {
         return Ok(());
     }
 
-    if retry {
-        use std::io::Read;
-        let mut stdin = std::io::stdin();
-
-        while let Err(err) = quiren(&dir, Args { mode, dryrun, trash }) {
-            eprintln!("Error: {}", err);
-            eprintln!("Press enter to retry");
-
-            let _ = stdin.read(&mut [0u8]);
-        }
-        return Ok(());
-    }
-
-    Ok(quiren(&dir, Args { mode, dryrun, trash })?)
+    Ok(quiren(
+        &dir,
+        Args {
+            mode,
+            dryrun,
+            trash,
+            retry,
+            allow_escape,
+            recursive,
+            max_depth,
+            no_rollback,
+        },
+    )?)
 }
 
 #[derive(Error, Debug)]
@@ -96,28 +127,102 @@ pub enum QuirenError {
     IoError(#[from] std::io::Error),
     #[error("error when trashing: {0}")]
     TrashError(#[from] trash::Error),
+    #[error("invalid substitute pattern: {0}")]
+    InvalidPattern(#[from] regex::Error),
+    #[error("multiple errors:\n{}", .0.iter().map(|(line, e)| format!("  line {}: {}", line + 1, e)).collect::<Vec<_>>().join("\n"))]
+    Multiple(Vec<(usize, QuirenError)>),
+    #[error("the new name '{0}' would move the file outside of dir (use --allow-escape to allow this)")]
+    PathEscape(String),
 }
 
-pub fn quiren(dir: &Path, args: Args) -> Result<(), QuirenError> {
-    let mut entries: Vec<_> = dir.read_dir()?.map(|e| e.unwrap()).collect();
+/// A single listed entry: its full filesystem path, plus the path relative
+/// to `dir` shown in (and read back from) the editor buffer. For a
+/// non-recursive listing `rel_path` is just the file name.
+struct Entry {
+    path: PathBuf,
+    rel_path: PathBuf,
+}
 
-    entries.sort_by_key(|e| e.file_name());
+/// Lists the entries under `dir`, recursing into subdirectories (up to
+/// `max_depth` levels, if given) when `recursive` is set, then sorts the
+/// result by relative path.
+fn collect_entries(dir: &Path, recursive: bool, max_depth: Option<usize>) -> io::Result<Vec<Entry>> {
+    let mut entries = if recursive {
+        walk_dir(dir, Path::new(""), max_depth, 0)?
+    } else {
+        dir.read_dir()?
+            .map(|e| e.unwrap())
+            .map(|e| Entry {
+                path: e.path(),
+                rel_path: PathBuf::from(e.file_name()),
+            })
+            .collect()
+    };
+
+    entries.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+
+    Ok(entries)
+}
+
+/// Recursively walks `root/rel`, parallelizing the per-entry `file_name()`
+/// and metadata work with `rayon` so that directories with tens of
+/// thousands of entries don't hang a single-threaded walk.
+fn walk_dir(
+    root: &Path,
+    rel: &Path,
+    max_depth: Option<usize>,
+    depth: usize,
+) -> io::Result<Vec<Entry>> {
+    let dir_entries: Vec<DirEntry> = root.join(rel).read_dir()?.collect::<io::Result<_>>()?;
+
+    let results: Vec<io::Result<Vec<Entry>>> = dir_entries
+        .par_iter()
+        .map(|entry| {
+            let rel_path = rel.join(entry.file_name());
+            let is_dir = entry.file_type()?.is_dir();
+
+            let mut found = vec![Entry {
+                path: entry.path(),
+                rel_path: rel_path.clone(),
+            }];
+
+            if is_dir && max_depth.is_none_or(|max| depth < max) {
+                found.extend(walk_dir(root, &rel_path, max_depth, depth + 1)?);
+            }
+
+            Ok(found)
+        })
+        .collect();
+
+    let mut entries = Vec::new();
+    for result in results {
+        entries.extend(result?);
+    }
+
+    Ok(entries)
+}
+
+pub fn quiren(dir: &Path, args: Args) -> Result<(), QuirenError> {
+    let entries = collect_entries(dir, args.recursive, args.max_depth)?;
 
     let text = entries
         .iter()
-        .map(|e| e.file_name())
+        .map(|e| e.rel_path.as_os_str())
         .concat("\n")
         .to_string_lossy()
         .into_owned();
 
-    let mut edited = edit::edit(&text)?;
-    let mut changes = Vec::new();
-
-    // We add the changes
-    match args.mode {
-        Mode::Rename => changes.extend(extract_renames(&edited, &dir, &entries)?),
-        Mode::Delete => changes.extend(extract_deletions(&edited, &entries)?),
-    }
+    let mut edited = match &args.mode {
+        // Substitute mode rewrites every name by regex instead of going
+        // through an editor round-trip.
+        Mode::Substitute {
+            pattern,
+            replacement,
+            literal,
+        } => substitute_names(pattern, replacement, *literal, &entries)?,
+        Mode::Rename | Mode::Delete => edit::edit(&text)?,
+    };
+    let mut changes = extract_with_retry(&args, &mut edited, &dir, &entries)?;
 
     if args.dryrun {
         loop {
@@ -126,11 +231,109 @@ pub fn quiren(dir: &Path, args: Args) -> Result<(), QuirenError> {
             }
 
             edited = edit::edit(&edited)?;
-            changes.clear();
+            changes = extract_with_retry(&args, &mut edited, &dir, &entries)?;
+        }
+    }
+
+    let mut journal: Vec<JournalEntry> = Vec::new();
+
+    if let Err(err) = apply_changes(&args, &changes, &mut journal) {
+        if !args.no_rollback {
+            rollback(journal);
+        }
+        return Err(err);
+    }
+
+    Ok(())
+}
+
+enum Change {
+    // Rename: file_a -> file_b
+    Rename(PathBuf, PathBuf),
+    // Delete: file_a
+    Delete(PathBuf),
+}
 
-            match args.mode {
-                Mode::Rename => changes.extend(extract_renames(&edited, &dir, &entries)?),
-                Mode::Delete => changes.extend(extract_deletions(&edited, &entries)?),
+/// A single filesystem mutation performed while applying `changes`, recorded
+/// so `rollback` can undo it if a later step in the same batch fails.
+enum JournalEntry {
+    Renamed(PathBuf, PathBuf),
+    Exchanged(PathBuf, PathBuf),
+    MkdirAll(PathBuf),
+    Trashed(trash::TrashItem),
+}
+
+/// Undoes every entry in `journal`, most recent first. Best-effort: a
+/// failure to undo one step is reported but doesn't stop the rest of the
+/// rollback from being attempted.
+fn rollback(journal: Vec<JournalEntry>) {
+    for entry in journal.into_iter().rev() {
+        let result = match entry {
+            JournalEntry::Renamed(from, to) => fs::rename(&to, &from).map_err(|e| e.to_string()),
+            JournalEntry::Exchanged(a, b) => exchange(&a, &b).map_err(|e| e.to_string()),
+            JournalEntry::MkdirAll(path) => {
+                // Safe to remove outright: everything under it was created
+                // by this batch and the rename(s) into it were already
+                // undone by the time we get here.
+                fs::remove_dir_all(&path).map_err(|e| e.to_string())
+            }
+            JournalEntry::Trashed(item) => {
+                trash::os_limited::restore_all(vec![item]).map_err(|e| e.to_string())
+            }
+        };
+
+        if let Err(err) = result {
+            eprintln!("Warning: failed to roll back a change: {}", err);
+        }
+    }
+}
+
+/// Returns the highest ancestor of `path` that doesn't exist yet, i.e. the
+/// directory `fs::create_dir_all(path)` would actually create first. `None`
+/// if `path` already exists.
+fn topmost_new_ancestor(path: &Path) -> Option<PathBuf> {
+    if path.exists() {
+        return None;
+    }
+
+    let mut highest = path.to_path_buf();
+    let mut current = path.to_path_buf();
+
+    while current.pop() && !current.exists() {
+        highest = current.clone();
+    }
+
+    Some(highest)
+}
+
+/// Applies the resolved `changes` to the filesystem, recording every
+/// mutation into `journal` as it goes.
+fn apply_changes(
+    args: &Args,
+    changes: &[Change],
+    journal: &mut Vec<JournalEntry>,
+) -> Result<(), QuirenError> {
+    // Resolve rename cycles (e.g. `a -> b`, `b -> a`) as atomic
+    // `renameat2(RENAME_EXCHANGE)` swaps up front, so they never hit the
+    // overwrite check below or need a tempfile at all.
+    let mut rename_graph: HashMap<&Path, &Path> = HashMap::new();
+    for change in changes {
+        if let Change::Rename(a, b) = change {
+            rename_graph.insert(a.as_path(), b.as_path());
+        }
+    }
+
+    let mut exchanged: HashSet<PathBuf> = HashSet::new();
+    for change in changes {
+        if let Change::Rename(a, _) = change {
+            if exchanged.contains(a) {
+                continue;
+            }
+
+            if let Some(cycle) = find_rename_cycle(&rename_graph, a) {
+                if apply_rename_cycle(&cycle, journal)? {
+                    exchanged.extend(cycle);
+                }
             }
         }
     }
@@ -141,6 +344,7 @@ pub fn quiren(dir: &Path, args: Args) -> Result<(), QuirenError> {
     // Perform the filesystem operations.
     for change in changes.iter() {
         match change {
+            Change::Rename(a, _) if exchanged.contains(a) => continue,
             Change::Rename(a, b) => {
                 // Check if a file already exists at the new name
                 if b.exists() {
@@ -161,6 +365,7 @@ pub fn quiren(dir: &Path, args: Args) -> Result<(), QuirenError> {
 
                     let aux = tmpfile(b.parent().unwrap())?;
                     fs::rename(b, &aux)?;
+                    journal.push(JournalEntry::Renamed(b.to_path_buf(), aux.clone()));
                     moved_to_tempfile.insert(b, aux);
                 }
 
@@ -171,9 +376,35 @@ pub fn quiren(dir: &Path, args: Args) -> Result<(), QuirenError> {
                     &a
                 };
 
-                fs::rename(a, b)?
+                // `b` may point into a subdirectory that doesn't exist yet.
+                if let Some(parent) = b.parent() {
+                    if let Some(new_root) = topmost_new_ancestor(parent) {
+                        journal.push(JournalEntry::MkdirAll(new_root));
+                    }
+                    fs::create_dir_all(parent)?;
+                }
+
+                fs::rename(a, b)?;
+                journal.push(JournalEntry::Renamed(a.to_path_buf(), b.to_path_buf()));
+            }
+            Change::Delete(a) if args.trash => {
+                // `trash::delete` doesn't hand back a restorable item, so we
+                // diff `os_limited::list()` around the delete to find the
+                // `TrashItem` it created and journal that instead.
+                // `trash` canonicalizes paths before trashing them, so `a`
+                // (possibly relative) has to be canonicalized the same way
+                // to match `item.original_path()`.
+                let canonical_a = fs::canonicalize(a)?;
+                let before: HashSet<_> = trash::os_limited::list()?.into_iter().collect();
+                trash::delete(a)?;
+                let item = trash::os_limited::list()?
+                    .into_iter()
+                    .find(|item| !before.contains(item) && item.original_path() == canonical_a);
+
+                if let Some(item) = item {
+                    journal.push(JournalEntry::Trashed(item));
+                }
             }
-            Change::Delete(a) if args.trash => trash::delete(a)?,
             Change::Delete(a) => fs::remove_file(a)?,
         }
     }
@@ -181,55 +412,286 @@ pub fn quiren(dir: &Path, args: Args) -> Result<(), QuirenError> {
     Ok(())
 }
 
-enum Change {
-    // Rename: file_a -> file_b
-    Rename(PathBuf, PathBuf),
-    // Delete: file_a
-    Delete(PathBuf),
+/// Walks the `src -> dst` rename graph starting at `start` and returns the
+/// ordered nodes of the cycle it belongs to (e.g. `a -> b, b -> a`), or
+/// `None` if `start` isn't part of one.
+fn find_rename_cycle(graph: &HashMap<&Path, &Path>, start: &Path) -> Option<Vec<PathBuf>> {
+    let mut cycle = vec![start.to_path_buf()];
+    let mut current = start.to_path_buf();
+
+    loop {
+        let next = (*graph.get(current.as_path())?).to_path_buf();
+
+        if next == start {
+            return Some(cycle);
+        }
+
+        if cycle.contains(&next) {
+            // `start` merely feeds into a cycle it isn't part of.
+            return None;
+        }
+
+        cycle.push(next.clone());
+        current = next;
+    }
+}
+
+/// Applies a resolved rename cycle as a chain of atomic exchanges: the head
+/// is swapped with each following node in turn, which takes `k - 1`
+/// exchanges to rotate a `k`-cycle into place. Returns `Ok(false)` instead
+/// of erroring when `renameat2` isn't supported, so the caller can fall
+/// back to the tempfile dance.
+fn apply_rename_cycle(
+    cycle: &[PathBuf],
+    journal: &mut Vec<JournalEntry>,
+) -> Result<bool, QuirenError> {
+    if cycle.len() < 2 {
+        return Ok(false);
+    }
+
+    let head = &cycle[0];
+
+    for (i, node) in cycle[1..].iter().enumerate() {
+        if let Err(err) = exchange(head, node) {
+            let unsupported = matches!(
+                (err.kind(), err.raw_os_error()),
+                (io::ErrorKind::Unsupported, _)
+                    | (_, Some(libc::ENOSYS))
+                    | (_, Some(libc::EINVAL))
+            );
+
+            // Only a handful of platforms don't support `renameat2` at all,
+            // so we only take this as a signal to fall back to the tempfile
+            // dance if nothing in this cycle has been swapped yet. Once the
+            // rotation is underway, a later failure can't be "unsupported"
+            // (the earlier exchange just succeeded) and the filesystem is
+            // already half-permuted, so it has to be a hard error and let
+            // the journal rollback undo the completed swaps.
+            if unsupported && i == 0 {
+                return Ok(false);
+            }
+
+            return Err(err.into());
+        }
+
+        journal.push(JournalEntry::Exchanged(head.clone(), node.clone()));
+    }
+
+    Ok(true)
+}
+
+/// Builds an `edited`-style buffer (one new name per line, in entry order)
+/// by applying a regex substitution to every entry's listed path (its file
+/// name, or its whole relative path under `--recursive`), so it can be
+/// fed straight into `extract_renames`.
+fn substitute_names(
+    pattern: &str,
+    replacement: &str,
+    literal: bool,
+    entries: &[Entry],
+) -> Result<String, QuirenError> {
+    let pattern = if literal {
+        regex::escape(pattern)
+    } else {
+        pattern.to_owned()
+    };
+    let regex = regex::Regex::new(&pattern)?;
+
+    let lines: Vec<String> = entries
+        .iter()
+        .map(|e| {
+            let name = e.rel_path.to_string_lossy().into_owned();
+            regex.replace_all(&name, replacement).into_owned()
+        })
+        .collect();
+
+    Ok(lines.join("\n"))
+}
+
+/// Dispatches to `extract_renames`/`extract_deletions` for the current mode
+/// and collects the result, so callers don't have to match on `Mode` twice.
+fn extract_changes(
+    args: &Args,
+    edited: &str,
+    dir: &Path,
+    entries: &Vec<Entry>,
+    annotations: &HashSet<String>,
+) -> Result<Vec<Change>, QuirenError> {
+    match &args.mode {
+        Mode::Rename | Mode::Substitute { .. } => {
+            Ok(extract_renames(edited, dir, entries, args.allow_escape, annotations)?.collect())
+        }
+        Mode::Delete => Ok(extract_deletions(edited, entries, annotations)?.collect()),
+    }
+}
+
+/// Extracts changes from `edited`, re-entering the editor with inline
+/// `# error: ...` annotations on failure when `args.retry` is set, instead
+/// of bailing out on the first problem.
+fn extract_with_retry(
+    args: &Args,
+    edited: &mut String,
+    dir: &Path,
+    entries: &Vec<Entry>,
+) -> Result<Vec<Change>, QuirenError> {
+    // The exact text of every `# error: ...` line we've injected so far, so
+    // `extract_renames`/`extract_deletions` can skip precisely those lines
+    // instead of guessing from a leading `#`, which a real entry name could
+    // also start with.
+    let mut annotations: HashSet<String> = HashSet::new();
+
+    loop {
+        match extract_changes(args, edited, dir, entries, &annotations) {
+            Ok(changes) => return Ok(changes),
+            Err(err) if args.retry => {
+                eprintln!("Error: {}", err);
+                let annotated;
+                (annotated, annotations) = annotate_errors(edited, &err, &annotations);
+                *edited = edit::edit(&annotated)?;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Re-annotates `edited` with an inline `# error: ...` comment above every
+/// line a `QuirenError::Multiple` blames, so `--retry` lets the user fix
+/// every problem in one editor pass instead of one-at-a-time. `err`'s line
+/// indices are positions into the entries with `prior` annotations already
+/// filtered out, so we have to skip those same lines here to land comments
+/// on the right entry. Returns the annotated buffer alongside the exact
+/// text of every line it's injected so far, `prior` included, so annotations
+/// accumulate across retries instead of being lost each round.
+fn annotate_errors(
+    edited: &str,
+    err: &QuirenError,
+    prior: &HashSet<String>,
+) -> (String, HashSet<String>) {
+    let mut by_line: HashMap<usize, Vec<String>> = HashMap::new();
+
+    match err {
+        QuirenError::Multiple(errors) => {
+            for (line, e) in errors {
+                by_line.entry(*line).or_default().push(e.to_string());
+            }
+        }
+        // No specific line to blame; surface it above the first entry.
+        other => by_line.entry(0).or_default().push(other.to_string()),
+    }
+
+    let mut out = String::new();
+    let mut injected = prior.clone();
+
+    for (i, line) in edited.lines().filter(|line| !prior.contains(*line)).enumerate() {
+        if let Some(messages) = by_line.get(&i) {
+            for message in messages {
+                let comment = format!("# error: {}", message);
+                out.push_str(&comment);
+                out.push('\n');
+                injected.insert(comment);
+            }
+        }
+        out.push_str(line);
+        out.push('\n');
+    }
+
+    (out, injected)
+}
+
+/// Resolves `.`/`..` components in `path` purely lexically, since
+/// `Path::canonicalize` requires the path to already exist.
+fn normalize_path(path: &Path) -> PathBuf {
+    use std::path::Component;
+
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                out.pop();
+            }
+            Component::CurDir => {}
+            other => out.push(other.as_os_str()),
+        }
+    }
+    out
 }
 
 /// Returns an iterator with all the renames found.
 fn extract_renames<'a>(
     edited: &'a str,
     dir: &'a Path,
-    entries: &'a Vec<DirEntry>,
+    entries: &'a Vec<Entry>,
+    allow_escape: bool,
+    annotations: &HashSet<String>,
 ) -> Result<impl Iterator<Item = Change> + 'a, QuirenError> {
-    // Check if linecount = entry count
-    let new_count = edited.lines().count();
+    // Lines left over from a `--retry` annotation don't count; matched
+    // against the exact text we injected, not just a leading `#`, so a real
+    // entry whose name happens to start with one isn't dropped.
+    let lines: Vec<&str> = edited
+        .lines()
+        .filter(|line| !annotations.contains(*line))
+        .collect();
 
-    if new_count != entries.len() {
-        return Err(QuirenError::EntryCountMismatch(entries.len(), new_count));
+    // Check if linecount = entry count
+    if lines.len() != entries.len() {
+        return Err(QuirenError::EntryCountMismatch(entries.len(), lines.len()));
     }
 
-    // Other checks
-    for (i, a) in edited.lines().enumerate() {
+    // Collect every problem instead of bailing on the first, so `--retry`
+    // can annotate them all at once.
+    let mut errors = Vec::new();
+    // Made absolute first: a relative `dir` such as `.` would normalize to
+    // an empty path, which every target (even `../x`) starts with.
+    let abs_dir = std::path::absolute(dir)?;
+    let normalized_dir = normalize_path(&abs_dir);
+
+    // The target each line resolves to once `.`/`..` are collapsed, so two
+    // lexically different lines that land on the same file (e.g. `sub/x.txt`
+    // and `sub/../sub/x.txt`) are caught as duplicates instead of slipping
+    // through to a confusing `Overwrite` at apply time.
+    let targets: Vec<PathBuf> = lines.iter().map(|line| normalize_path(&abs_dir.join(line))).collect();
+
+    for (i, a) in lines.iter().enumerate() {
         // Check for empty names
         if a.is_empty() {
-            return Err(QuirenError::EmptyName(
-                entries[i].file_name().to_string_lossy().to_string(),
+            errors.push((
+                i,
+                QuirenError::EmptyName(entries[i].rel_path.to_string_lossy().to_string()),
             ));
+            continue;
+        }
+
+        // A name containing a path separator moves the entry into a
+        // (possibly new) subdirectory; make sure it can't escape `dir`.
+        if !allow_escape && !targets[i].starts_with(&normalized_dir) {
+            errors.push((i, QuirenError::PathEscape(a.to_string())));
+            continue;
         }
 
         // Check for duplicates
-        for (j, b) in edited.lines().enumerate() {
-            if i != j && a == b {
-                return Err(QuirenError::DuplicateName(a.to_string()));
+        for (j, _) in lines.iter().enumerate() {
+            if i != j && targets[i] == targets[j] {
+                errors.push((i, QuirenError::DuplicateName(a.to_string())));
+                break;
             }
         }
     }
 
-    let iter = edited
-        .lines()
+    if !errors.is_empty() {
+        return Err(QuirenError::Multiple(errors));
+    }
+
+    let iter = lines
+        .into_iter()
         .enumerate()
         // Only rename files with modified names
         .filter(move |(i, line)| {
             let name = OsStr::new(line);
-            name != entries[*i].file_name()
+            name != entries[*i].rel_path.as_os_str()
         })
         .map(move |(i, line)| {
-            let mut new_path = dir.to_owned();
-            new_path.push(line);
-            Change::Rename(entries[i].path(), new_path)
+            let new_path = dir.join(line);
+            Change::Rename(entries[i].path.clone(), new_path)
         });
 
     Ok(iter)
@@ -238,21 +700,23 @@ fn extract_renames<'a>(
 /// Returns an iterator with all the deletions found.
 fn extract_deletions<'a>(
     edited: &'a str,
-    entries: &'a Vec<DirEntry>,
+    entries: &'a Vec<Entry>,
+    annotations: &HashSet<String>,
 ) -> Result<impl Iterator<Item = Change> + 'a, QuirenError> {
     // Delete files that have been deleted in the editor and return
     // Managing deletion AND rename is too complex. Users must perform
     // there operations separately
 
-    let r: Vec<OsString> = edited
+    let r: Vec<PathBuf> = edited
         .lines()
-        .map(OsString::from)
-        .collect::<Vec<OsString>>();
+        .filter(|line| !annotations.contains(*line))
+        .map(PathBuf::from)
+        .collect();
 
     let iter = entries
         .iter()
-        .filter(move |existed| !r.contains(&existed.file_name()))
-        .map(move |entry| Change::Delete(entry.path()));
+        .filter(move |existed| !r.contains(&existed.rel_path))
+        .map(move |entry| Change::Delete(entry.path.clone()));
 
     Ok(iter)
 }
@@ -263,7 +727,12 @@ fn confirm_changes(changes: &[Change], trash: bool) -> bool {
 
     for change in changes {
         match change {
-            Change::Rename(a, b) => println!("Rename: {} -> {}", a.display(), b.display()),
+            Change::Rename(a, b) => {
+                if b.parent().is_some_and(|parent| !parent.exists()) {
+                    println!("Mkdir: {}", b.parent().unwrap().display());
+                }
+                println!("Rename: {} -> {}", a.display(), b.display())
+            }
             Change::Delete(a) => println!("{}: {}", delete_action, a.display()),
         }
     }
@@ -281,3 +750,145 @@ fn confirm_changes(changes: &[Change], trash: bool) -> bool {
 
     answer == Answer::YES
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_rename_cycle_detects_two_cycle() {
+        let a = Path::new("/dir/a");
+        let b = Path::new("/dir/b");
+        let graph: HashMap<&Path, &Path> = HashMap::from([(a, b), (b, a)]);
+
+        let cycle = find_rename_cycle(&graph, a).unwrap();
+        assert_eq!(cycle, vec![a.to_path_buf(), b.to_path_buf()]);
+    }
+
+    #[test]
+    fn find_rename_cycle_detects_longer_cycle() {
+        let a = Path::new("/dir/a");
+        let b = Path::new("/dir/b");
+        let c = Path::new("/dir/c");
+        let graph: HashMap<&Path, &Path> = HashMap::from([(a, b), (b, c), (c, a)]);
+
+        let cycle = find_rename_cycle(&graph, b).unwrap();
+        assert_eq!(
+            cycle,
+            vec![b.to_path_buf(), c.to_path_buf(), a.to_path_buf()]
+        );
+    }
+
+    #[test]
+    fn find_rename_cycle_none_for_chain_into_unrelated_cycle() {
+        // `start -> a -> b -> a`: `start` feeds into a cycle but isn't part
+        // of it, so no cycle should be reported for `start`.
+        let start = Path::new("/dir/start");
+        let a = Path::new("/dir/a");
+        let b = Path::new("/dir/b");
+        let graph: HashMap<&Path, &Path> = HashMap::from([(start, a), (a, b), (b, a)]);
+
+        assert!(find_rename_cycle(&graph, start).is_none());
+    }
+
+    #[test]
+    fn find_rename_cycle_none_for_dangling_chain() {
+        let a = Path::new("/dir/a");
+        let b = Path::new("/dir/b");
+        let graph: HashMap<&Path, &Path> = HashMap::from([(a, b)]);
+
+        assert!(find_rename_cycle(&graph, a).is_none());
+    }
+
+    #[test]
+    fn extract_renames_aggregates_every_error_instead_of_bailing_early() {
+        let dir = PathBuf::from("/dir");
+        let entries = vec![
+            Entry {
+                path: dir.join("a.txt"),
+                rel_path: PathBuf::from("a.txt"),
+            },
+            Entry {
+                path: dir.join("b.txt"),
+                rel_path: PathBuf::from("b.txt"),
+            },
+            Entry {
+                path: dir.join("c.txt"),
+                rel_path: PathBuf::from("c.txt"),
+            },
+        ];
+        let edited = "\nsame.txt\nsame.txt";
+        let annotations = HashSet::new();
+
+        let err = extract_renames(edited, &dir, &entries, false, &annotations)
+            .err()
+            .expect("empty name + duplicate names should fail");
+
+        match err {
+            QuirenError::Multiple(errors) => {
+                assert_eq!(errors.len(), 3, "expected every problem to be reported: {:?}", errors);
+                assert!(matches!(errors[0], (0, QuirenError::EmptyName(_))));
+                assert!(matches!(errors[1], (1, QuirenError::DuplicateName(_))));
+                assert!(matches!(errors[2], (2, QuirenError::DuplicateName(_))));
+            }
+            other => panic!("expected QuirenError::Multiple, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn annotate_errors_accumulates_across_retries_and_indexes_filtered_lines() {
+        // Round 1: entry 1 (`b.txt` -> empty) is blamed.
+        let edited = "a.txt\n\nc.txt";
+        let err = QuirenError::Multiple(vec![(1, QuirenError::EmptyName("b.txt".into()))]);
+        let prior = HashSet::new();
+
+        let (annotated, annotations_after_round_1) = annotate_errors(edited, &err, &prior);
+        assert_eq!(annotations_after_round_1.len(), 1);
+        assert_eq!(
+            annotated,
+            "a.txt\n# error: the entry 'b.txt' was assigned an empty name\n\nc.txt\n"
+        );
+
+        // Round 2: the buffer still holds the stale round-1 annotation
+        // comment, and a fresh problem is reported against the
+        // *filtered* (non-annotation) line 2, which is `c.txt`.
+        let err2 = QuirenError::Multiple(vec![(2, QuirenError::DuplicateName("c.txt".into()))]);
+        let (annotated2, annotations_after_round_2) =
+            annotate_errors(&annotated, &err2, &annotations_after_round_1);
+
+        // The round-1 annotation must still be recognized (cumulative, not
+        // replaced), and the round-2 comment must land above `c.txt`, not
+        // above the stale comment line itself.
+        assert!(annotations_after_round_2.len() > annotations_after_round_1.len());
+        let lines: Vec<&str> = annotated2.lines().collect();
+        let c_txt_pos = lines.iter().position(|l| *l == "c.txt").unwrap();
+        assert_eq!(lines[c_txt_pos - 1], "# error: the filename c.txt is duplicated");
+    }
+
+    #[test]
+    fn topmost_new_ancestor_is_none_for_an_existing_path() {
+        assert!(topmost_new_ancestor(&std::env::temp_dir()).is_none());
+    }
+
+    #[test]
+    fn topmost_new_ancestor_finds_the_highest_missing_directory() {
+        let base = std::env::temp_dir().join(format!(
+            "quiren-test-{}-topmost-new-ancestor",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&base);
+
+        // `base` and everything below it are missing while its parent
+        // exists; `base/a/b` should report `base` as
+        // the highest ancestor `create_dir_all` would actually create, not
+        // `base/a` or the full nested path.
+        let nested = base.join("a").join("b");
+        assert_eq!(topmost_new_ancestor(&nested), Some(base.clone()));
+
+        fs::create_dir_all(&base).unwrap();
+        assert!(topmost_new_ancestor(&nested).is_some());
+        assert_ne!(topmost_new_ancestor(&nested), Some(base.clone()));
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -3,6 +3,7 @@ use rand::{distributions::Alphanumeric, rngs::SmallRng};
 use rand::{Rng, SeedableRng};
 use std::cell::UnsafeCell;
 use std::ffi::OsString;
+use std::io;
 use std::path::{Path, PathBuf};
 
 const TEMPFILE_MAX_RETRIES: usize = 20;
@@ -36,3 +37,39 @@ pub fn tmpfile(path: &Path) -> Result<PathBuf, QuirenError> {
 
     Err(QuirenError::Tempfile)
 }
+
+/// Atomically swaps the files at `a` and `b` via `renameat2(2)` with
+/// `RENAME_EXCHANGE`, so a two-file rename cycle needs no tempfile at all.
+/// Returns an `ENOSYS`/`EINVAL` `io::Error` when the kernel or filesystem
+/// doesn't support the exchange; callers should fall back to a rename dance.
+#[cfg(target_os = "linux")]
+pub fn exchange(a: &Path, b: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let a = CString::new(a.as_os_str().as_bytes())?;
+    let b = CString::new(b.as_os_str().as_bytes())?;
+
+    let ret = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            a.as_ptr(),
+            libc::AT_FDCWD,
+            b.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// `renameat2` is Linux-only, so elsewhere we just report it as unsupported
+/// and let the caller fall back to the tempfile-based rename dance.
+#[cfg(not(target_os = "linux"))]
+pub fn exchange(_a: &Path, _b: &Path) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}